@@ -1,68 +1,147 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{SystemTime, Duration, UNIX_EPOCH};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
+use tokio::sync::mpsc;
 
+/// Starting capacity for a connection's read buffer (two pages). Grows by
+/// doubling whenever a single frame doesn't fit.
+const INITIAL_BUFFER_SIZE: usize = 8 * 1024;
+// Mirrors Redis's `proto-max-bulk-len` default. Caps how large a single
+// declared RESP length (bulk string byte count or array element count) is
+// allowed to be, so a client can't make the per-connection buffer grow
+// without bound just by sending a huge `$<len>` header and trickling data.
+const MAX_RESP_LEN: usize = 512 * 1024 * 1024;
+
+/// How often the background sweeper samples the dictionary for expired keys.
+const EXPIRE_SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+/// Keys sampled per sweep cycle, mirroring Redis's own active-expiry cycle.
+const EXPIRE_SAMPLE_SIZE: usize = 20;
 
 struct Command {
     name: CommandName,
-    operands: Vec<String>
+    operands: Vec<Vec<u8>>,
+    set_options: Option<SetOptions>,
 }
 
 struct ValueAndExpiration {
-    value: String,
+    value: Vec<u8>,
     expiration: Option<u128>
 }
 
+/// How a SET's expiration should be applied: `EX`/`PX` set a new TTL,
+/// `KEEPTTL` carries the existing one forward, and the default clears it.
+enum SetExpiration {
+    None,
+    KeepTtl,
+    ExpireAfterSeconds(u64),
+    ExpireAfterMillis(u64),
+}
+
+/// NX/XX gate whether the write happens at all; the default always writes.
+enum SetCondition {
+    Always,
+    OnlyIfAbsent,
+    OnlyIfPresent,
+}
+
+/// Parsed SET option grammar: `[EX seconds | PX milliseconds | KEEPTTL]
+/// [NX | XX] [GET]`.
+struct SetOptions {
+    expiration: SetExpiration,
+    condition: SetCondition,
+    return_old_value: bool,
+}
+
 type KeyValueDict = Arc<Mutex<HashMap<String, ValueAndExpiration>>>;
 
-fn dispatcher(command: &Command, redis_dict: &KeyValueDict) -> String {
+/// Channel name -> senders for every connection currently subscribed to
+/// it. Each connection owns the receiving half and forwards whatever
+/// arrives straight to its socket.
+type PubSubRegistry = Arc<Mutex<HashMap<String, Vec<mpsc::UnboundedSender<Vec<u8>>>>>>;
+
+fn dispatcher(
+    command: &Command,
+    redis_dict: &KeyValueDict,
+    expiring_keys: &ExpiringKeys,
+    pubsub: &PubSubRegistry,
+    tx: &mpsc::UnboundedSender<Vec<u8>>,
+    subscriptions: &mut Vec<String>,
+) -> Vec<u8> {
     match command.name {
         CommandName::PING => {
-            return String::from("+PONG\r\n");
+            return Vec::from(&b"+PONG\r\n"[..]);
         },
         CommandName::ECHO => {
-            return format!("+{}\r\n", command.operands[0]);
+            let mut reply = Vec::with_capacity(command.operands[0].len() + 3);
+            reply.push(b'+');
+            reply.extend_from_slice(&command.operands[0]);
+            reply.extend_from_slice(b"\r\n");
+            return reply;
         },
         CommandName::SET => {
-            let value: ValueAndExpiration;
-            if command.operands.len() == 2 {
-                println!("Command just saves the key, no expiration time {}: {}", command.operands[0], command.operands[1]);
-                value = ValueAndExpiration{
-                    value: command.operands[1].to_string(),
-                    expiration: None,
-                };
+            let key = String::from_utf8_lossy(&command.operands[0]).into_owned();
+            let new_value = command.operands[1].clone();
+            let options = command.set_options.as_ref().expect("SET command always carries parsed options");
+
+            // Lock `expiring_keys` before `redis_dict` everywhere, matching
+            // the order `sweep_expired_keys` takes, so the two never deadlock.
+            let mut expiring = expiring_keys.lock().unwrap();
+            let mut dict = redis_dict.lock().unwrap();
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+            let (existing_value, existing_expiration) = match dict.get(&key) {
+                Some(v) if v.expiration.is_some_and(|exp| exp < now) => (None, None),
+                Some(v) => (Some(v.value.clone()), v.expiration),
+                None => (None, None),
+            };
+            let key_exists = existing_value.is_some();
+
+            let condition_met = match options.condition {
+                SetCondition::Always => true,
+                SetCondition::OnlyIfAbsent => !key_exists,
+                SetCondition::OnlyIfPresent => key_exists,
+            };
+            let old_value_reply = if options.return_old_value {
+                Some(match &existing_value {
+                    Some(v) => construct_return_redis_string(v),
+                    None => Vec::from(&b"$-1\r\n"[..]),
+                })
             } else {
-                println!("Command saves the key, with expiration time {}: {} ({})", command.operands[0], command.operands[1], command.operands[2]);
-                value = ValueAndExpiration{
-                    value: command.operands[1].to_string(),
-                    expiration: Some(
-                        SystemTime::now().checked_add(
-                            Duration::from_millis(
-                                command.operands[2].parse().unwrap())
-                        ).unwrap()
-                        .duration_since(UNIX_EPOCH).unwrap().as_millis()
-                    ),
-                };
+                None
+            };
+
+            if !condition_met {
+                return old_value_reply.unwrap_or_else(|| Vec::from(&b"$-1\r\n"[..]));
             }
-            {
-                let mut dict = redis_dict.lock().unwrap();
-                dict.insert(command.operands[0].to_string(), value);
+
+            let expiration = match options.expiration {
+                SetExpiration::None => None,
+                SetExpiration::KeepTtl => existing_expiration,
+                SetExpiration::ExpireAfterSeconds(secs) => Some(now + secs as u128 * 1000),
+                SetExpiration::ExpireAfterMillis(millis) => Some(now + millis as u128),
+            };
+            match expiration {
+                Some(_) => expiring.insert(key.clone()),
+                None => expiring.remove(&key),
             }
-            return String::from("+OK\r\n");
+            println!("Command saves the key {}: {:?} (expiration {:?})", key, new_value, expiration);
+            dict.insert(key, ValueAndExpiration{ value: new_value, expiration });
+
+            return old_value_reply.unwrap_or_else(|| Vec::from(&b"+OK\r\n"[..]));
         },
         CommandName::GET => {
-            let key = &command.operands[0];
+            let key = String::from_utf8_lossy(&command.operands[0]).into_owned();
             let dict = redis_dict.lock().unwrap();
-            match dict.get(key) {
+            match dict.get(&key) {
                 Some(value) => {
                     match value.expiration {
                         Some(exp) => {
                             let time_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
                             if time_now > exp {
                                 println!("Value with key {:?} has expired. Value was {:?}. Expiration time was {:?} < time now {:?}", key, value.value, exp, time_now);
-                                return String::from("$-1\r\n");
+                                return Vec::from(&b"$-1\r\n"[..]);
                             }
                             return construct_return_redis_string(&value.value);
                         },
@@ -70,124 +149,774 @@ fn dispatcher(command: &Command, redis_dict: &KeyValueDict) -> String {
                             return construct_return_redis_string(&value.value);
                         },
                     }
-                    
+
                 },
                 None => {
-                    return String::from("$-1\r\n");
+                    return Vec::from(&b"$-1\r\n"[..]);
                 },
             }
         },
-    } 
+        CommandName::SUBSCRIBE => {
+            let channel = String::from_utf8_lossy(&command.operands[0]).into_owned();
+            {
+                let mut registry = pubsub.lock().unwrap();
+                let senders = registry.entry(channel.clone()).or_default();
+                if !senders.iter().any(|sender| sender.same_channel(tx)) {
+                    senders.push(tx.clone());
+                }
+            }
+            if !subscriptions.contains(&channel) {
+                subscriptions.push(channel.clone());
+            }
+            return construct_subscribe_reply("subscribe", &channel, subscriptions.len());
+        },
+        CommandName::UNSUBSCRIBE => {
+            let channel = String::from_utf8_lossy(&command.operands[0]).into_owned();
+            {
+                let mut registry = pubsub.lock().unwrap();
+                if let Some(senders) = registry.get_mut(&channel) {
+                    senders.retain(|sender| !sender.same_channel(tx));
+                }
+            }
+            subscriptions.retain(|subscribed| subscribed != &channel);
+            return construct_subscribe_reply("unsubscribe", &channel, subscriptions.len());
+        },
+        CommandName::PUBLISH => {
+            let channel = String::from_utf8_lossy(&command.operands[0]).into_owned();
+            let message = construct_pubsub_message(&channel, &command.operands[1]);
+            let mut registry = pubsub.lock().unwrap();
+            let delivered = match registry.get_mut(&channel) {
+                Some(senders) => {
+                    senders.retain(|sender| sender.send(message.clone()).is_ok());
+                    senders.len()
+                },
+                None => 0,
+            };
+            return format!(":{}\r\n", delivered).into_bytes();
+        },
+    }
+}
+
+fn construct_return_redis_string(val: &[u8]) -> Vec<u8> {
+    println!("key to return {:?}", val);
+    let mut reply = Vec::with_capacity(val.len() + 16);
+    reply.extend_from_slice(format!("${}\r\n", val.len()).as_bytes());
+    reply.extend_from_slice(val);
+    reply.extend_from_slice(b"\r\n");
+    reply
+}
+
+/// Builds the RESP reply Redis sends back on SUBSCRIBE/UNSUBSCRIBE:
+/// `*3\r\n$<len>\r\n<kind>\r\n$<len>\r\n<channel>\r\n:<count>\r\n`, where `count` is the
+/// connection's total subscription count after the change.
+fn construct_subscribe_reply(kind: &str, channel: &str, count: usize) -> Vec<u8> {
+    let mut reply = Vec::new();
+    reply.extend_from_slice(b"*3\r\n");
+    reply.extend_from_slice(format!("${}\r\n{}\r\n", kind.len(), kind).as_bytes());
+    reply.extend_from_slice(format!("${}\r\n{}\r\n", channel.len(), channel).as_bytes());
+    reply.extend_from_slice(format!(":{}\r\n", count).as_bytes());
+    reply
+}
+
+/// Builds the RESP array a PUBLISH fans out to subscribers:
+/// `*3\r\n$7\r\nmessage\r\n$<len>\r\n<channel>\r\n$<len>\r\n<payload>\r\n`.
+fn construct_pubsub_message(channel: &str, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::new();
+    message.extend_from_slice(b"*3\r\n$7\r\nmessage\r\n");
+    message.extend_from_slice(format!("${}\r\n{}\r\n", channel.len(), channel).as_bytes());
+    message.extend_from_slice(format!("${}\r\n", payload.len()).as_bytes());
+    message.extend_from_slice(payload);
+    message.extend_from_slice(b"\r\n");
+    message
+}
+
+/// Seed for `next_random_u64`, reseeded from the clock on first use and then
+/// advanced in place by each call.
+static SWEEP_RNG_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Cheap xorshift64 PRNG. Good enough to pick an unbiased-looking sample of
+/// keys per sweep without pulling in a `rand` dependency for one call site.
+fn next_random_u64() -> u64 {
+    let mut x = SWEEP_RNG_STATE.load(Ordering::Relaxed);
+    if x == 0 {
+        x = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64 | 1;
+    }
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    SWEEP_RNG_STATE.store(x, Ordering::Relaxed);
+    x
+}
+
+/// Keys that currently carry a TTL, tracked alongside `redis_dict` so the
+/// sweeper can pick random candidates in O(1) instead of walking every
+/// key in the keyspace each tick. `positions` maps a key to its index in
+/// `keys` so both insertion and removal avoid a linear search.
+struct ExpiringKeyIndex {
+    keys: Vec<String>,
+    positions: HashMap<String, usize>,
+}
+
+impl ExpiringKeyIndex {
+    fn new() -> Self {
+        ExpiringKeyIndex { keys: Vec::new(), positions: HashMap::new() }
+    }
+
+    /// Starts tracking `key` as carrying a TTL. A no-op if it's already tracked.
+    fn insert(&mut self, key: String) {
+        if self.positions.contains_key(&key) {
+            return;
+        }
+        self.positions.insert(key.clone(), self.keys.len());
+        self.keys.push(key);
+    }
+
+    /// Stops tracking `key`, swapping the last entry into its slot so
+    /// removal never has to shift the rest of `keys` down.
+    fn remove(&mut self, key: &str) {
+        if let Some(pos) = self.positions.remove(key) {
+            let last = self.keys.len() - 1;
+            self.keys.swap(pos, last);
+            self.keys.pop();
+            if pos < self.keys.len() {
+                let moved = self.keys[pos].clone();
+                self.positions.insert(moved, pos);
+            }
+        }
+    }
+
+    /// Shuffles up to `n` random keys to the front of `keys` (a partial
+    /// Fisher-Yates shuffle) and returns a copy of them. Cost is O(n),
+    /// not O(len()), since keys past position `n` are never inspected.
+    fn sample(&mut self, n: usize) -> Vec<String> {
+        let n = n.min(self.keys.len());
+        for i in 0..n {
+            let j = i + (next_random_u64() as usize) % (self.keys.len() - i);
+            self.keys.swap(i, j);
+            self.positions.insert(self.keys[i].clone(), i);
+            self.positions.insert(self.keys[j].clone(), j);
+        }
+        self.keys[..n].to_vec()
+    }
 }
 
-fn construct_return_redis_string(val: &String) -> String {
-    println!("key to return {}", val);
-    return format!("${}\r\n{}\r\n", val.len(), val);
+type ExpiringKeys = Arc<Mutex<ExpiringKeyIndex>>;
+
+/// Samples up to `EXPIRE_SAMPLE_SIZE` keys-with-TTL out of `expiring_keys`
+/// and evicts those past their expiration from both it and `redis_dict`.
+/// Never walks the whole keyspace: each sampling round costs
+/// O(EXPIRE_SAMPLE_SIZE) regardless of how many keys exist. Repeats
+/// immediately if more than a quarter of the sample was expired, the same
+/// heuristic Redis's own active-expire cycle uses to keep up with a key
+/// space that's mostly full of stale entries; the number of rounds in one
+/// call is not capped.
+fn sweep_expired_keys(redis_dict: &KeyValueDict, expiring_keys: &ExpiringKeys) {
+    loop {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let mut expiring = expiring_keys.lock().unwrap();
+        let sample = expiring.sample(EXPIRE_SAMPLE_SIZE);
+        let sampled = sample.len();
+        if sampled == 0 {
+            return;
+        }
+
+        let mut dict = redis_dict.lock().unwrap();
+        let mut expired = 0;
+        for key in sample {
+            match dict.get(&key).and_then(|v| v.expiration) {
+                Some(exp) if exp < now => {
+                    dict.remove(&key);
+                    expiring.remove(&key);
+                    expired += 1;
+                },
+                Some(_) => {},
+                // The key's TTL was cleared or the key itself was removed
+                // since it was indexed; either way it no longer belongs here.
+                None => expiring.remove(&key),
+            }
+        }
+        drop(dict);
+        drop(expiring);
+
+        if expired * 4 <= sampled {
+            return;
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let listener: TcpListener = TcpListener::bind("127.0.0.1:6379").await.unwrap();
     let redis_dict: KeyValueDict = Arc::new(Mutex::new(HashMap::new()));
-    
+    let expiring_keys: ExpiringKeys = Arc::new(Mutex::new(ExpiringKeyIndex::new()));
+    let pubsub: PubSubRegistry = Arc::new(Mutex::new(HashMap::new()));
+
+    let sweeper_dict = Arc::clone(&redis_dict);
+    let sweeper_expiring_keys = Arc::clone(&expiring_keys);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(EXPIRE_SWEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            sweep_expired_keys(&sweeper_dict, &sweeper_expiring_keys);
+        }
+    });
+
     loop {
         let (mut stream, _) = listener.accept().await.unwrap();
         let redis_dict_ref = Arc::clone(&redis_dict);
+        let expiring_keys_ref = Arc::clone(&expiring_keys);
+        let pubsub_ref = Arc::clone(&pubsub);
         tokio::spawn(async move {
             println!("accepted new connection");
-            let mut command: Command;
+            // `filled` is how much of `buf[..]` holds bytes read from the
+            // socket but not yet consumed by the parser.
+            let mut buf = vec![0u8; INITIAL_BUFFER_SIZE];
+            let mut filled: usize = 0;
+            // Published messages for channels this connection subscribes to
+            // arrive on `rx` and are interleaved with client commands below.
+            let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+            let mut subscriptions: Vec<String> = Vec::new();
             loop {
-                let mut buf = [0; 512];
-                let bytes_read = stream.read(&mut buf).await.unwrap();
-                if bytes_read == 0 {
-                    println!("client closed the connection");
-                    break;
-                } else {
-                    command = parse_redis_command(&buf);
+                if filled == buf.len() {
+                    buf.resize(buf.len() * 2, 0);
+                }
+                tokio::select! {
+                    read_result = stream.read(&mut buf[filled..]) => {
+                        let bytes_read = read_result.unwrap();
+                        if bytes_read == 0 {
+                            println!("client closed the connection");
+                            break;
+                        }
+                        filled += bytes_read;
+
+                        let mut cursor = 0;
+                        while let Some((parsed, consumed)) = parse_redis_command(&buf[cursor..filled]) {
+                            cursor += consumed;
+                            let response = match parsed {
+                                Ok(command) => dispatcher(&command, &redis_dict_ref, &expiring_keys_ref, &pubsub_ref, &tx, &mut subscriptions),
+                                Err(error_reply) => error_reply.into_bytes(),
+                            };
+                            stream.write_all(&response).await.unwrap();
+                        }
+
+                        // Shift the unparsed remainder (an incomplete frame) down to
+                        // the front of the buffer so the next read appends after it.
+                        if cursor > 0 {
+                            buf.copy_within(cursor..filled, 0);
+                            filled -= cursor;
+                        }
+                    }
+                    Some(message) = rx.recv() => {
+                        stream.write_all(&message).await.unwrap();
+                    }
                 }
-                let response = dispatcher(&command, &redis_dict_ref);
-                stream.write(response.as_bytes()).await.unwrap();
             }
         });
     }
 }
 
-fn parse_bulk_string(buf: &[u8; 512], ptr: &mut usize) -> String {
-    assert!(buf[*ptr] == ('$' as u8), "expect bulk strings to start with $");
-    *ptr += 1;
-    let mut len: u8 = 0;
-    let zero: u8 = '0' as u8;
-    while buf[*ptr] != ('\r' as u8) {
-        len = len * 10 + (buf[*ptr] - zero);
-        *ptr += 1;
+/// Parses the digits of a RESP length prefix (the `<len>` in `*<len>\r\n`
+/// or `$<len>\r\n`), starting at `buf[start]` and reading up to the
+/// terminating `\r`. Returns `None` if `buf` doesn't yet hold the
+/// terminator. A byte that isn't an ASCII digit, or a length that would
+/// overflow `usize` or exceed `MAX_RESP_LEN`, is reported as the
+/// already-formatted RESP error; the caller has lost any reliable resync
+/// point at that point, so it treats the error as consuming the whole
+/// buffer it was handed.
+fn parse_length(buf: &[u8], start: usize) -> Option<Result<(usize, usize), String>> {
+    let mut ptr = start;
+    let mut len: usize = 0;
+    loop {
+        if ptr >= buf.len() {
+            return None;
+        }
+        if buf[ptr] == b'\r' {
+            return Some(Ok((len, ptr)));
+        }
+        if !buf[ptr].is_ascii_digit() {
+            return Some(Err(syntax_error()));
+        }
+        let digit = (buf[ptr] - b'0') as usize;
+        len = match len.checked_mul(10).and_then(|v| v.checked_add(digit)) {
+            Some(len) if len <= MAX_RESP_LEN => len,
+            _ => return Some(Err(frame_too_large_error())),
+        };
+        ptr += 1;
     }
-    *ptr += 2;
-    let mut result = String::new();
-    for _ in 0..len {
-        result.push(char::from(buf[*ptr]));
-        *ptr += 1;
+}
+
+/// Parses a RESP bulk string (`$<len>\r\n<data>\r\n`) from the front of
+/// `buf`. The payload is copied out as raw bytes, not decoded as UTF-8, so
+/// binary values round-trip correctly. Returns `None` if `buf` doesn't yet
+/// hold a complete frame, in which case the caller should read more bytes
+/// and retry rather than treating it as malformed. A malformed length
+/// prefix yields `Some((Err(_), buf.len()))` instead of panicking, since
+/// framing is unrecoverable once the length digits are garbage.
+fn parse_bulk_string(buf: &[u8]) -> Option<(Result<Vec<u8>, String>, usize)> {
+    if buf.is_empty() {
+        return None;
     }
-    *ptr += 2;
-    result
+    assert!(buf[0] == b'$', "expect bulk strings to start with $");
+    let (len, mut ptr) = match parse_length(buf, 1)? {
+        Ok(parsed) => parsed,
+        Err(err) => return Some((Err(err), buf.len())),
+    };
+    if ptr + 1 >= buf.len() {
+        return None;
+    }
+    ptr += 2;
+
+    if ptr + len + 2 > buf.len() {
+        return None;
+    }
+    let result = buf[ptr..ptr + len].to_vec();
+    ptr += len + 2;
+    Some((Ok(result), ptr))
 }
+
 enum CommandName {
     PING,
     ECHO,
     SET,
     GET,
+    SUBSCRIBE,
+    UNSUBSCRIBE,
+    PUBLISH,
 }
 
+/// Outcome of parsing a RESP array: either its elements paired with how
+/// far the cursor advanced, or an already-formatted RESP error.
+type ArrayParseResult = Option<(Result<Vec<Vec<u8>>, String>, usize)>;
 
-fn parse_array(buf: &[u8; 512], ptr: &mut usize) -> Vec<String> {
-    *ptr += 1;
-
-    let mut len: u8 = 0;
-    let zero: u8 = '0' as u8;
-    while buf[*ptr] != ('\r' as u8) {
-        len = len * 10 + (buf[*ptr] - zero);
-        *ptr += 1;
+/// Parses a RESP array (`*<len>\r\n<elements>`) from the front of `buf`.
+/// Returns `None` if the array header or any of its elements aren't fully
+/// buffered yet. A malformed length prefix, on the array itself or on any
+/// element, yields `Some((Err(_), buf.len()))` instead of panicking, since
+/// framing is unrecoverable once the length digits are garbage.
+fn parse_array(buf: &[u8]) -> ArrayParseResult {
+    if buf.is_empty() {
+        return None;
+    }
+    assert!(buf[0] == b'*', "expect arrays to start with *");
+    let (len, mut ptr) = match parse_length(buf, 1)? {
+        Ok(parsed) => parsed,
+        Err(err) => return Some((Err(err), buf.len())),
+    };
+    if ptr + 1 >= buf.len() {
+        return None;
     }
-    *ptr += 2;
+    ptr += 2;
 
-    let mut result: Vec<String> = Vec::new();
+    let mut result: Vec<Vec<u8>> = Vec::new();
     for _ in 0..len {
-        result.push(parse_bulk_string(buf, ptr));
+        match parse_bulk_string(&buf[ptr..])? {
+            (Ok(element), consumed) => {
+                result.push(element);
+                ptr += consumed;
+            },
+            (Err(err), _consumed) => return Some((Err(err), buf.len())),
+        }
     }
-    result
+    Some((Ok(result), ptr))
+}
+
+
+fn unknown_command_error(name: &str) -> String {
+    format!("-ERR unknown command '{}'\r\n", name)
+}
+
+fn wrong_arity_error(name: &str) -> String {
+    format!("-ERR wrong number of arguments for '{}' command\r\n", name)
+}
+
+fn syntax_error() -> String {
+    String::from("-ERR syntax error\r\n")
 }
 
+fn frame_too_large_error() -> String {
+    String::from("-ERR Protocol error: invalid bulk length\r\n")
+}
 
-fn parse_redis_command(buf: &[u8; 512]) -> Command {
-    let mut ptr: usize = 0;
-    let elements = parse_array(buf, &mut ptr);
+/// Parses the SET option tail (everything after `key value`): `EX
+/// seconds`, `PX milliseconds`, `NX`, `XX`, `GET` and `KEEPTTL`, in any
+/// order. Returns a RESP syntax error if a flag is unknown, duplicated
+/// with a conflicting one, or a numeric flag is missing its argument.
+fn parse_set_options(tokens: &[Vec<u8>]) -> Result<SetOptions, String> {
+    let mut expiration = SetExpiration::None;
+    let mut condition = SetCondition::Always;
+    let mut return_old_value = false;
+    let mut expiration_set = false;
+    let mut condition_set = false;
 
-    // ping
-    if elements[0].to_lowercase() == "ping" {
-        return Command{
+    let mut i = 0;
+    while i < tokens.len() {
+        let flag = String::from_utf8_lossy(&tokens[i]).to_uppercase();
+        match flag.as_str() {
+            "EX" | "PX" => {
+                if expiration_set {
+                    return Err(syntax_error());
+                }
+                i += 1;
+                let raw = tokens.get(i).ok_or_else(syntax_error)?;
+                let amount: u64 = std::str::from_utf8(raw).ok()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(syntax_error)?;
+                expiration = if flag == "EX" {
+                    SetExpiration::ExpireAfterSeconds(amount)
+                } else {
+                    SetExpiration::ExpireAfterMillis(amount)
+                };
+                expiration_set = true;
+            },
+            "KEEPTTL" => {
+                if expiration_set {
+                    return Err(syntax_error());
+                }
+                expiration = SetExpiration::KeepTtl;
+                expiration_set = true;
+            },
+            "NX" => {
+                if condition_set {
+                    return Err(syntax_error());
+                }
+                condition = SetCondition::OnlyIfAbsent;
+                condition_set = true;
+            },
+            "XX" => {
+                if condition_set {
+                    return Err(syntax_error());
+                }
+                condition = SetCondition::OnlyIfPresent;
+                condition_set = true;
+            },
+            "GET" => return_old_value = true,
+            _ => return Err(syntax_error()),
+        }
+        i += 1;
+    }
+
+    Ok(SetOptions{expiration, condition, return_old_value})
+}
+
+/// Parses one full RESP command from the front of `buf`. Returns `None`
+/// when `buf` holds an incomplete frame so the caller can wait for more
+/// bytes from the socket instead of indexing past what's been read.
+/// Once a complete frame is available, the inner `Result` reports whether
+/// it described a command the server understands: `Err` carries an
+/// already-formatted RESP error reply for an unknown command, wrong
+/// arity, or bad SET option syntax, which the caller writes straight back
+/// to the client.
+fn parse_redis_command(buf: &[u8]) -> Option<(Result<Command, String>, usize)> {
+    if buf.is_empty() {
+        return None;
+    }
+    let (elements_result, consumed) = parse_array(buf)?;
+    let elements = match elements_result {
+        Ok(elements) => elements,
+        Err(err) => return Some((Err(err), consumed)),
+    };
+    if elements.is_empty() {
+        return Some((Err(unknown_command_error("")), consumed));
+    }
+    let name = String::from_utf8_lossy(&elements[0]).to_lowercase();
+
+    if name == "ping" {
+        return Some((Ok(Command{
             name: CommandName::PING,
             operands: vec![],
-        };
-    } else if elements[0].to_lowercase() == "echo" {
-        return Command{
+            set_options: None,
+        }), consumed));
+    } else if name == "echo" {
+        if elements.len() != 2 {
+            return Some((Err(wrong_arity_error(&name)), consumed));
+        }
+        return Some((Ok(Command{
             name: CommandName::ECHO,
-            operands: vec![elements[1].to_string()],
-        };
-    } else if elements[0].to_lowercase() == "set" {
-        let mut operands = vec![elements[1].to_string(), elements[2].to_string()];
-        if elements.len() > 3 {
-            operands.push(elements[4].to_string());
+            operands: vec![elements[1].clone()],
+            set_options: None,
+        }), consumed));
+    } else if name == "set" {
+        if elements.len() < 3 {
+            return Some((Err(wrong_arity_error(&name)), consumed));
         }
-        return Command{
-            name: CommandName::SET,
-            operands: operands,
+        let options = match parse_set_options(&elements[3..]) {
+            Ok(options) => options,
+            Err(error) => return Some((Err(error), consumed)),
         };
-    } else if elements[0].to_lowercase() == "get" {
-        return Command{
+        return Some((Ok(Command{
+            name: CommandName::SET,
+            operands: vec![elements[1].clone(), elements[2].clone()],
+            set_options: Some(options),
+        }), consumed));
+    } else if name == "get" {
+        if elements.len() != 2 {
+            return Some((Err(wrong_arity_error(&name)), consumed));
+        }
+        return Some((Ok(Command{
             name: CommandName::GET,
-            operands: vec![elements[1].to_string()],
-        };
+            operands: vec![elements[1].clone()],
+            set_options: None,
+        }), consumed));
+    } else if name == "subscribe" {
+        if elements.len() != 2 {
+            return Some((Err(wrong_arity_error(&name)), consumed));
+        }
+        return Some((Ok(Command{
+            name: CommandName::SUBSCRIBE,
+            operands: vec![elements[1].clone()],
+            set_options: None,
+        }), consumed));
+    } else if name == "unsubscribe" {
+        if elements.len() != 2 {
+            return Some((Err(wrong_arity_error(&name)), consumed));
+        }
+        return Some((Ok(Command{
+            name: CommandName::UNSUBSCRIBE,
+            operands: vec![elements[1].clone()],
+            set_options: None,
+        }), consumed));
+    } else if name == "publish" {
+        if elements.len() != 3 {
+            return Some((Err(wrong_arity_error(&name)), consumed));
+        }
+        return Some((Ok(Command{
+            name: CommandName::PUBLISH,
+            operands: vec![elements[1].clone(), elements[2].clone()],
+            set_options: None,
+        }), consumed));
     } else {
-        panic!("Unknown command received")
+        return Some((Err(unknown_command_error(&name)), consumed));
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `parts` to `parse_redis_command` one at a time, compacting the
+    /// buffer after each part exactly like the connection loop does. This
+    /// stands in for a live `TcpStream` delivering a command split across
+    /// however many reads `parts` represents.
+    fn feed_parts(parts: &[&[u8]]) -> Vec<Result<Command, String>> {
+        let mut buf: Vec<u8> = Vec::new();
+        let mut results = Vec::new();
+        for part in parts {
+            buf.extend_from_slice(part);
+            let mut cursor = 0;
+            while let Some((parsed, consumed)) = parse_redis_command(&buf[cursor..]) {
+                cursor += consumed;
+                results.push(parsed);
+            }
+            buf.drain(..cursor);
+        }
+        results
+    }
+
+    fn command_name(result: &Result<Command, String>) -> &'static str {
+        match result {
+            Ok(command) => match command.name {
+                CommandName::PING => "ping",
+                CommandName::ECHO => "echo",
+                CommandName::SET => "set",
+                CommandName::GET => "get",
+                CommandName::SUBSCRIBE => "subscribe",
+                CommandName::UNSUBSCRIBE => "unsubscribe",
+                CommandName::PUBLISH => "publish",
+            },
+            Err(_) => "error",
+        }
+    }
+
+    #[test]
+    fn resumes_across_arbitrary_byte_boundaries() {
+        let frame = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nhello\r\n";
+        for split_at in 1..frame.len() {
+            let (first, second) = frame.split_at(split_at);
+            let results = feed_parts(&[first, second]);
+            assert_eq!(results.len(), 1, "split_at={} should still yield exactly one command", split_at);
+            assert_eq!(command_name(&results[0]), "set");
+        }
+    }
+
+    #[test]
+    fn resumes_when_split_mid_multibyte_utf8_sequence() {
+        // "café" stored as the SET value: the 'é' is the two-byte UTF-8
+        // sequence 0xC3 0xA9. The split lands between those two bytes, so
+        // the parser must treat the value as opaque bytes rather than
+        // trying (and failing) to decode a partial character.
+        let value = "café".as_bytes();
+        let mut frame = Vec::new();
+        frame.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n");
+        frame.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+        frame.extend_from_slice(value);
+        frame.extend_from_slice(b"\r\n");
+
+        let split_at = frame.len() - 3;
+        let (first, second) = frame.split_at(split_at);
+        let results = feed_parts(&[first, second]);
+
+        assert_eq!(results.len(), 1);
+        match &results[0] {
+            Ok(command) => assert_eq!(command.operands[1].as_slice(), value),
+            Err(_) => panic!("expected SET to parse successfully"),
+        }
+    }
+
+    #[test]
+    fn resumes_when_split_between_dollar_length_and_payload() {
+        let frame = b"*1\r\n$4\r\nPING\r\n";
+        let split_at = frame.iter().position(|&b| b == b'P').unwrap();
+        let (first, second) = frame.split_at(split_at);
+        let results = feed_parts(&[first, second]);
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(command_name(&results[0]), "ping");
+    }
+
+    #[test]
+    fn handles_a_bulk_length_crossing_the_256_byte_boundary() {
+        let payload = vec![b'x'; 300];
+        let mut frame = Vec::new();
+        frame.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n");
+        frame.extend_from_slice(format!("${}\r\n", payload.len()).as_bytes());
+        frame.extend_from_slice(&payload);
+        frame.extend_from_slice(b"\r\n");
+
+        let results = feed_parts(&[&frame]);
+
+        assert_eq!(results.len(), 1);
+        match &results[0] {
+            Ok(command) => assert_eq!(command.operands[1].len(), 300),
+            Err(_) => panic!("expected SET to parse successfully"),
+        }
+    }
+
+    #[test]
+    fn parses_two_pipelined_commands_delivered_in_one_buffer() {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+        frame.extend_from_slice(b"*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n");
+
+        let results = feed_parts(&[&frame]);
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(command_name(&results[0]), "ping");
+        assert_eq!(command_name(&results[1]), "echo");
+    }
+
+    #[test]
+    fn reports_incomplete_instead_of_panicking_on_a_bare_prefix() {
+        assert!(parse_redis_command(b"*1\r\n$4\r\nPI").is_none());
+    }
+
+    #[test]
+    fn reports_an_error_instead_of_panicking_on_an_empty_array() {
+        let results = feed_parts(&[b"*0\r\n"]);
+        assert_eq!(results.len(), 1);
+        assert_eq!(command_name(&results[0]), "error");
+    }
+
+    #[test]
+    fn reports_an_error_instead_of_panicking_on_a_non_digit_length_byte() {
+        assert!(matches!(parse_array(b"*\x01\r\n"), Some((Err(_), _))));
+    }
+
+    #[test]
+    fn reports_an_error_instead_of_panicking_on_an_oversized_bulk_length() {
+        let frame = b"$999999999999999999999999\r\nx\r\n";
+        assert!(matches!(parse_bulk_string(frame), Some((Err(_), _))));
+    }
+
+    #[test]
+    fn rejects_a_bulk_length_over_the_max_resp_len_without_buffering_its_payload() {
+        let frame = b"$2000000000\r\n";
+        assert!(matches!(parse_bulk_string(frame), Some((Err(_), consumed)) if consumed == frame.len()));
+    }
+
+    #[test]
+    fn rejects_conflicting_nx_and_xx_flags_on_set() {
+        let tokens = vec![b"NX".to_vec(), b"XX".to_vec()];
+        assert!(parse_set_options(&tokens).is_err());
+    }
+
+    #[test]
+    fn rejects_conflicting_expiration_flags_on_set() {
+        let tokens = vec![b"EX".to_vec(), b"10".to_vec(), b"PX".to_vec(), b"1000".to_vec()];
+        assert!(parse_set_options(&tokens).is_err());
+    }
+
+    #[test]
+    fn resubscribing_to_the_same_channel_does_not_duplicate_the_registry_entry() {
+        let redis_dict: KeyValueDict = Arc::new(Mutex::new(HashMap::new()));
+        let expiring_keys: ExpiringKeys = Arc::new(Mutex::new(ExpiringKeyIndex::new()));
+        let pubsub: PubSubRegistry = Arc::new(Mutex::new(HashMap::new()));
+        let (tx, _rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let mut subscriptions: Vec<String> = Vec::new();
+        let command = Command {
+            name: CommandName::SUBSCRIBE,
+            operands: vec![b"chan".to_vec()],
+            set_options: None,
+        };
+
+        dispatcher(&command, &redis_dict, &expiring_keys, &pubsub, &tx, &mut subscriptions);
+        dispatcher(&command, &redis_dict, &expiring_keys, &pubsub, &tx, &mut subscriptions);
+
+        let registry = pubsub.lock().unwrap();
+        assert_eq!(registry.get("chan").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn set_nx_treats_a_lazily_expired_key_as_absent() {
+        let redis_dict: KeyValueDict = Arc::new(Mutex::new(HashMap::new()));
+        let expiring_keys: ExpiringKeys = Arc::new(Mutex::new(ExpiringKeyIndex::new()));
+        let pubsub: PubSubRegistry = Arc::new(Mutex::new(HashMap::new()));
+        let (tx, _rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let mut subscriptions: Vec<String> = Vec::new();
+
+        redis_dict.lock().unwrap().insert("k".to_string(), ValueAndExpiration {
+            value: b"old".to_vec(),
+            expiration: Some(1),
+        });
+
+        let command = Command {
+            name: CommandName::SET,
+            operands: vec![b"k".to_vec(), b"new".to_vec()],
+            set_options: Some(SetOptions {
+                expiration: SetExpiration::None,
+                condition: SetCondition::OnlyIfAbsent,
+                return_old_value: false,
+            }),
+        };
+
+        let reply = dispatcher(&command, &redis_dict, &expiring_keys, &pubsub, &tx, &mut subscriptions);
+        assert_eq!(reply, b"+OK\r\n".to_vec());
+    }
+
+    #[test]
+    fn sampling_rotates_through_the_keyspace_instead_of_repeating_a_fixed_prefix() {
+        let mut index = ExpiringKeyIndex::new();
+        for i in 0..1000 {
+            index.insert(i.to_string());
+        }
+        let mut seen: std::collections::HashSet<String> = std::collections::HashSet::new();
+        for _ in 0..20 {
+            seen.extend(index.sample(20));
+        }
+        assert!(seen.len() > 20, "repeated sampling should cover more than one fixed prefix of keys");
+    }
+
+    #[test]
+    fn expiring_key_index_remove_keeps_remaining_keys_sampleable() {
+        let mut index = ExpiringKeyIndex::new();
+        for key in ["a", "b", "c"] {
+            index.insert(key.to_string());
+        }
+        index.remove("b");
+
+        let mut seen: std::collections::HashSet<String> = std::collections::HashSet::new();
+        for _ in 0..10 {
+            seen.extend(index.sample(3));
+        }
+        assert_eq!(seen, ["a", "c"].iter().map(|s| s.to_string()).collect());
+    }
+}